@@ -0,0 +1,192 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Runtime registry of consensus engine factories, keyed by the engine name
+//! used in the chainspec's `"engine"` object (`"Null"`, `"Ethash"`,
+//! `"AuthorityRound"`, ...).
+//!
+//! `Spec::engine` used to be a hard-coded match over `ethjson::spec::Engine`,
+//! flagged in the source as `TODO avoid this hard-coded nastiness - use
+//! dynamic-linked plugin framework instead`. This registry is that framework:
+//! the built-in engines are pre-registered under their names, and downstream
+//! crates can call `Spec::register_engine` with their own factory for one of
+//! these names (to swap in an alternative implementation) before loading a
+//! chainspec that refers to it, without patching this crate.
+//!
+//! A factory is handed the already-parsed `ethjson::spec::Engine` value for
+//! the name it was registered under, so there is no need to re-encode it to
+//! JSON and back just to cross the registry boundary.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use engines::{Engine, NullEngine, InstantSeal, BasicAuthority, AuthorityRound, Tendermint};
+use error::Error;
+use ethereum;
+use ethjson;
+use machine::EthereumMachine;
+
+/// Builds a consensus engine from the shared machine (chain params and builtins)
+/// and the chainspec's already-parsed `"engine"` value. `cache_dir` is passed
+/// through for engines (like `Ethash`) that need on-disk scratch space.
+pub type EngineFactory = Box<Fn(&Path, Arc<EthereumMachine>, ethjson::spec::Engine) -> Result<Arc<Engine>, Error> + Send + Sync>;
+
+lazy_static! {
+	static ref REGISTRY: RwLock<HashMap<String, EngineFactory>> = RwLock::new(default_engines());
+}
+
+/// The registry key for a given parsed engine spec, i.e. the name of the
+/// variant it was parsed from (`"Null"`, `"Ethash"`, ...).
+pub fn engine_name(engine_spec: &ethjson::spec::Engine) -> &'static str {
+	match *engine_spec {
+		ethjson::spec::Engine::Null => "Null",
+		ethjson::spec::Engine::InstantSeal => "InstantSeal",
+		ethjson::spec::Engine::Ethash(_) => "Ethash",
+		ethjson::spec::Engine::BasicAuthority(_) => "BasicAuthority",
+		ethjson::spec::Engine::AuthorityRound(_) => "AuthorityRound",
+		ethjson::spec::Engine::Tendermint(_) => "Tendermint",
+	}
+}
+
+fn insert_default(map: &mut HashMap<String, EngineFactory>, name: &str, factory: EngineFactory) {
+	map.insert(name.to_owned(), factory);
+}
+
+fn default_engines() -> HashMap<String, EngineFactory> {
+	let mut map: HashMap<String, EngineFactory> = HashMap::new();
+
+	insert_default(&mut map, "Null", Box::new(|_cache_dir, machine, _engine_spec| {
+		Ok(Arc::new(NullEngine::new(machine.params().clone(), machine.builtins().clone())) as Arc<Engine>)
+	}));
+
+	insert_default(&mut map, "InstantSeal", Box::new(|_cache_dir, machine, _engine_spec| {
+		Ok(Arc::new(InstantSeal::new(machine.params().clone(), machine.builtins().clone())) as Arc<Engine>)
+	}));
+
+	insert_default(&mut map, "Ethash", Box::new(|cache_dir, machine, engine_spec| {
+		match engine_spec {
+			ethjson::spec::Engine::Ethash(ethash) => Ok(Arc::new(ethereum::Ethash::new(
+				cache_dir,
+				machine.params().clone(),
+				From::from(ethash.params),
+				machine.builtins().clone(),
+			)) as Arc<Engine>),
+			_ => Err(Error::from("Ethash engine factory invoked with a non-Ethash engine spec".to_owned())),
+		}
+	}));
+
+	insert_default(&mut map, "BasicAuthority", Box::new(|_cache_dir, machine, engine_spec| {
+		match engine_spec {
+			ethjson::spec::Engine::BasicAuthority(basic_authority) => Ok(Arc::new(BasicAuthority::new(
+				machine.params().clone(),
+				From::from(basic_authority.params),
+				machine.builtins().clone(),
+			)) as Arc<Engine>),
+			_ => Err(Error::from("BasicAuthority engine factory invoked with a non-BasicAuthority engine spec".to_owned())),
+		}
+	}));
+
+	insert_default(&mut map, "AuthorityRound", Box::new(|_cache_dir, machine, engine_spec| {
+		match engine_spec {
+			ethjson::spec::Engine::AuthorityRound(authority_round) => AuthorityRound::new(
+				machine.params().clone(),
+				From::from(authority_round.params),
+				machine.builtins().clone(),
+			)
+				.map(|engine| engine as Arc<Engine>)
+				.map_err(|e| Error::from(format!("Failed to start AuthorityRound consensus engine: {}", e))),
+			_ => Err(Error::from("AuthorityRound engine factory invoked with a non-AuthorityRound engine spec".to_owned())),
+		}
+	}));
+
+	insert_default(&mut map, "Tendermint", Box::new(|_cache_dir, machine, engine_spec| {
+		match engine_spec {
+			ethjson::spec::Engine::Tendermint(tendermint) => Tendermint::new(
+				machine.params().clone(),
+				From::from(tendermint.params),
+				machine.builtins().clone(),
+			)
+				.map(|engine| engine as Arc<Engine>)
+				.map_err(|e| Error::from(format!("Failed to start the Tendermint consensus engine: {}", e))),
+			_ => Err(Error::from("Tendermint engine factory invoked with a non-Tendermint engine spec".to_owned())),
+		}
+	}));
+
+	map
+}
+
+/// Register a consensus engine factory under `name`, overriding any existing
+/// registration (including the built-ins) for that name. Must be called
+/// before loading a chainspec that refers to `name`.
+pub fn register_engine(name: &str, factory: EngineFactory) {
+	REGISTRY.write().expect("engine registry lock poisoned").insert(name.to_owned(), factory);
+}
+
+/// Build the engine registered under `name`, or an error if no engine is
+/// registered under that name.
+pub fn build_engine(name: &str, cache_dir: &Path, machine: Arc<EthereumMachine>, engine_spec: ethjson::spec::Engine) -> Result<Arc<Engine>, Error> {
+	let registry = REGISTRY.read().expect("engine registry lock poisoned");
+	match registry.get(name) {
+		Some(factory) => factory(cache_dir, machine, engine_spec),
+		None => Err(Error::from(format!("No consensus engine registered under the name '{}'", name))),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::Path;
+	use std::sync::Arc;
+	use error::Error;
+	use ethjson;
+	use machine::EthereumMachine;
+	use spec::CommonParams;
+	use super::{build_engine, register_engine};
+
+	// unique per test so registering/overriding a factory here can't race other
+	// tests that share the same process-wide `REGISTRY`.
+
+	#[test]
+	fn build_engine_invokes_the_registered_factory() {
+		register_engine("engine_registry::tests::invokes", Box::new(|_cache_dir, _machine, _engine_spec| {
+			Err(Error::from("called the registered factory".to_owned()))
+		}));
+
+		let machine = Arc::new(EthereumMachine::new(CommonParams::default(), Default::default()));
+		let err = build_engine("engine_registry::tests::invokes", Path::new("."), machine, ethjson::spec::Engine::Null).unwrap_err();
+		assert_eq!(format!("{}", err), "called the registered factory");
+	}
+
+	#[test]
+	fn register_engine_overrides_an_existing_registration() {
+		register_engine("engine_registry::tests::overrides", Box::new(|_cache_dir, _machine, _engine_spec| {
+			Err(Error::from("first".to_owned()))
+		}));
+		register_engine("engine_registry::tests::overrides", Box::new(|_cache_dir, _machine, _engine_spec| {
+			Err(Error::from("second".to_owned()))
+		}));
+
+		let machine = Arc::new(EthereumMachine::new(CommonParams::default(), Default::default()));
+		let err = build_engine("engine_registry::tests::overrides", Path::new("."), machine, ethjson::spec::Engine::Null).unwrap_err();
+		assert_eq!(format!("{}", err), "second", "register_engine should replace the prior factory, not add alongside it");
+	}
+
+	#[test]
+	fn build_engine_errors_for_an_unregistered_name() {
+		let machine = Arc::new(EthereumMachine::new(CommonParams::default(), Default::default()));
+		assert!(build_engine("engine_registry::tests::unregistered", Path::new("."), machine, ethjson::spec::Engine::Null).is_err());
+	}
+}
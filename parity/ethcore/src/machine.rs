@@ -0,0 +1,54 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The chain parameters and builtin contracts shared by every consensus
+//! engine for a given chain.
+//!
+//! This is deliberately *not* a home for chain-level behaviour (schedule
+//! selection, gas limit bounds, block rewards, ...) yet: `Engine` and
+//! `Executive` still read `CommonParams`/builtins out of their own
+//! constructor arguments rather than off a shared machine, since migrating
+//! them onto this type means changing every `engines::*` implementation and
+//! `executive.rs`, none of which are touched by this series. Once that
+//! migration lands, those chain-level methods belong here instead of on
+//! `CommonParams` or duplicated per engine; until then this only holds what
+//! `Spec` and the `engine_registry` factories actually read off it, so it
+//! doesn't carry API surface nothing calls.
+use std::collections::BTreeMap;
+
+use builtin::Builtin;
+use spec::CommonParams;
+use util::Address;
+
+/// Chain parameters and builtin contracts, shared by reference between
+/// `Spec` and the consensus engine it builds.
+pub struct EthereumMachine {
+	params: CommonParams,
+	builtins: BTreeMap<Address, Builtin>,
+}
+
+impl EthereumMachine {
+	/// Create a new `EthereumMachine` with the given chain parameters and builtins.
+	pub fn new(params: CommonParams, builtins: BTreeMap<Address, Builtin>) -> Self {
+		EthereumMachine { params: params, builtins: builtins }
+	}
+
+	/// Get the chain's common parameters.
+	pub fn params(&self) -> &CommonParams { &self.params }
+
+	/// Get the chain's builtin contracts, keyed by address.
+	pub fn builtins(&self) -> &BTreeMap<Address, Builtin> { &self.builtins }
+}
@@ -16,29 +16,30 @@
 
 //! Parameters for a block chain.
 
-use std::io::Read;
 use std::collections::BTreeMap;
+use std::io::Read;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::Arc;
 use rustc_hex::FromHex;
 use super::genesis::Genesis;
 use super::seal::Generic as GenericSeal;
 
 use builtin::Builtin;
-use engines::{Engine, NullEngine, InstantSeal, BasicAuthority, AuthorityRound, Tendermint, DEFAULT_BLOCKHASH_CONTRACT};
-use vm::{EnvInfo, CallType, ActionValue, ActionParams};
+use engine_registry;
+use engines::{Engine, DEFAULT_BLOCKHASH_CONTRACT};
 use error::Error;
-use ethereum;
 use ethjson;
-use executive::Executive;
 use factory::Factories;
 use header::{BlockNumber, Header};
+use machine::EthereumMachine;
 use pod_state::*;
 use rlp::{Rlp, RlpStream};
+use serde_json;
+use state_builder::StateBuilder;
 use state_db::StateDB;
-use state::{Backend, State, Substate};
+use state::Backend;
 use state::backend::Basic as BasicBackend;
-use trace::{NoopTracer, NoopVMTracer};
 use util::*;
 
 /// Parameters common to ethereum-like blockchains.
@@ -47,8 +48,7 @@ use util::*;
 ///
 /// we define a "bugfix" hard fork as any hard fork which
 /// you would put on-by-default in a new chain.
-#[derive(Debug, PartialEq, Default)]
-#[cfg_attr(test, derive(Clone))]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct CommonParams {
 	/// Account start nonce.
 	pub account_start_nonce: U256,
@@ -64,6 +64,8 @@ pub struct CommonParams {
 	pub min_gas_limit: U256,
 	/// Fork block to check.
 	pub fork_block: Option<(BlockNumber, H256)>,
+	/// Number of first block where Homestead rules begin.
+	pub homestead_transition: BlockNumber,
 	/// Number of first block where EIP-98 rules begin.
 	pub eip98_transition: BlockNumber,
 	/// Number of first block where EIP-155 rules begin.
@@ -110,8 +112,32 @@ impl CommonParams {
 		schedule
 	}
 
+	/// Whether Homestead rules are in effect at the given block.
+	pub fn is_homestead(&self, block_number: BlockNumber) -> bool {
+		block_number >= self.homestead_transition
+	}
+
+	/// Whether a transaction's signature `s` value is acceptable under `schedule`.
+	///
+	/// Before Homestead, any `s` in `(0, secp256k1n)` was accepted. EIP-2 (part of
+	/// Homestead) additionally rejects the "other" valid `s` for a given signature,
+	/// requiring `s <= secp256k1n / 2`, to remove transaction-hash malleability.
+	/// Driven by `schedule.reject_high_s_signatures`, set by `update_schedule` below,
+	/// so transaction verification only needs the schedule for the block it's
+	/// checking rather than the block number and a separate `CommonParams` lookup.
+	pub fn validate_transaction_signature(&self, schedule: &::vm::Schedule, s: &U256) -> bool {
+		if !schedule.reject_high_s_signatures {
+			return true;
+		}
+		*s <= U256::from_str("7fffffffffffffffffffffffffffffff5d576e7357a4501ddfe92f46681b20a0")
+			.expect("secp256k1n/2 constant is valid hex")
+	}
+
 	/// Apply common spec config parameters to the schedule.
  	pub fn update_schedule(&self, block_number: u64, schedule: &mut ::vm::Schedule) {
+		schedule.have_delegate_call = self.is_homestead(block_number);
+		schedule.exceptional_failed_code_deposit = self.is_homestead(block_number);
+		schedule.reject_high_s_signatures = self.is_homestead(block_number);
 		schedule.have_create2 = block_number >= self.eip86_transition;
 		schedule.have_revert = block_number >= self.eip140_transition;
 		schedule.have_static_call = block_number >= self.eip214_transition;
@@ -127,6 +153,41 @@ impl CommonParams {
 		}
 	}
 
+	/// Cross-check these params for internal consistency (fork transitions that
+	/// contradict each other, missing required fields, ...), returning a single
+	/// error listing every inconsistency found.
+	pub fn validate(&self) -> Result<(), String> {
+		let mut errors = Vec::new();
+
+		if self.eip86_transition < self.eip140_transition {
+			errors.push(format!("eip86_transition ({}) enables CREATE2 before eip140_transition ({}) enables REVERT", self.eip86_transition, self.eip140_transition));
+		}
+		if self.eip214_transition < self.eip211_transition {
+			errors.push(format!("eip214_transition ({}) enables STATICCALL before eip211_transition ({}) enables RETURNDATACOPY", self.eip214_transition, self.eip211_transition));
+		}
+		if self.eip210_transition != BlockNumber::max_value() && self.eip210_contract_code.is_empty() {
+			errors.push("eip210_transition is set but eip210_contract_code is empty".to_owned());
+		}
+		if self.gas_limit_bound_divisor.is_zero() {
+			errors.push("gas_limit_bound_divisor must not be zero".to_owned());
+		}
+		if self.min_gas_limit.is_zero() {
+			errors.push("min_gas_limit must not be zero".to_owned());
+		}
+		if self.network_id == 0 {
+			errors.push("network_id must be set".to_owned());
+		}
+		if self.chain_id == 0 {
+			errors.push("chain_id must be set".to_owned());
+		}
+
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(errors.join("; "))
+		}
+	}
+
 	/// Whether these params contain any bug-fix hard forks.
 	pub fn contains_bugfix_hard_fork(&self) -> bool {
 		self.eip98_transition != 0 &&
@@ -151,6 +212,7 @@ impl From<ethjson::spec::Params> for CommonParams {
 			subprotocol_name: p.subprotocol_name.unwrap_or_else(|| "eth".to_owned()),
 			min_gas_limit: p.min_gas_limit.into(),
 			fork_block: if let (Some(n), Some(h)) = (p.fork_block, p.fork_hash) { Some((n.into(), h.into())) } else { None },
+			homestead_transition: p.homestead_transition.map_or(0, Into::into),
 			eip98_transition: p.eip98_transition.map_or(0, Into::into),
 			eip155_transition: p.eip155_transition.map_or(0, Into::into),
 			validate_receipts_transition: p.validate_receipts_transition.map_or(0, Into::into),
@@ -182,6 +244,9 @@ pub struct Spec {
 	pub name: String,
 	/// What engine are we using for this?
 	pub engine: Arc<Engine>,
+	/// The shared machine (chain params and builtins) that `engine` was built
+	/// from. Schedule construction stays on `CommonParams`.
+	machine: Arc<EthereumMachine>,
 	/// Name of the subdir inside the main data dir to use for chain data and settings.
 	pub data_dir: String,
 
@@ -215,19 +280,26 @@ pub struct Spec {
 	/// May be prepopulated if we know this in advance.
 	state_root_memo: RwLock<H256>,
 
-	/// Genesis state as plain old data.
+	/// Genesis state as plain old data, before the constructors have run.
 	genesis_state: PodState,
+
+	/// The full genesis state, after the constructors have run. Set at load
+	/// time by `run_constructors`, or to `genesis_state` when the chainspec
+	/// gives a memoized state root.
+	constructed_state_memo: RwLock<PodState>,
 }
 
-fn load_from<T: AsRef<Path>>(cache_dir: T, s: ethjson::spec::Spec) -> Result<Spec, Error> {
-	let builtins = s.accounts.builtins().into_iter().map(|p| (p.0.into(), From::from(p.1))).collect();
+fn load_from<T: AsRef<Path>>(cache_dir: T, s: ethjson::spec::Spec, validate_spec: bool) -> Result<Spec, Error> {
+	let builtins: BTreeMap<Address, Builtin> = s.accounts.builtins().into_iter().map(|p| (p.0.into(), From::from(p.1))).collect();
 	let g = Genesis::from(s.genesis);
 	let GenericSeal(seal_rlp) = g.seal.into();
 	let params = CommonParams::from(s.params);
+	let machine = Arc::new(EthereumMachine::new(params, builtins));
 
 	let mut s = Spec {
 		name: s.name.clone().into(),
-		engine: Spec::engine(cache_dir, s.engine, params, builtins),
+		engine: Spec::engine(cache_dir, s.engine, machine.clone())?,
+		machine: machine,
 		data_dir: s.data_dir.unwrap_or(s.name).into(),
 		nodes: s.nodes.unwrap_or_else(Vec::new),
 		parent_hash: g.parent_hash,
@@ -243,14 +315,24 @@ fn load_from<T: AsRef<Path>>(cache_dir: T, s: ethjson::spec::Spec) -> Result<Spe
 		constructors: s.accounts.constructors().into_iter().map(|(a, c)| (a.into(), c.into())).collect(),
 		state_root_memo: RwLock::new(Default::default()), // will be overwritten right after.
 		genesis_state: s.accounts.into(),
+		constructed_state_memo: RwLock::new(Default::default()), // will be overwritten right after.
 	};
 
 	// use memoized state root if provided.
 	match g.state_root {
-		Some(root) => *s.state_root_memo.get_mut() = root,
+		Some(root) => {
+			*s.state_root_memo.get_mut() = root;
+			// constructors are not run against a memoized root, so the full state is
+			// taken to be the plain genesis state.
+			*s.constructed_state_memo.get_mut() = s.genesis_state.clone();
+		},
 		None => { let _ = s.run_constructors(&Default::default(), BasicBackend(MemoryDB::new()))?; },
 	}
 
+	if validate_spec {
+		s.validate()?;
+	}
+
 	Ok(s)
 }
 
@@ -264,105 +346,45 @@ macro_rules! load_bundled {
 }
 
 impl Spec {
-	/// Convert engine spec into a arc'd Engine of the right underlying type.
-	/// TODO avoid this hard-coded nastiness - use dynamic-linked plugin framework instead.
+	/// Convert engine spec into a arc'd Engine of the right underlying type, sharing
+	/// the given machine (chain params and builtins) with it.
+	///
+	/// Looks the engine up by name in the `engine_registry`, so third parties can
+	/// swap in their own factory for one of these names via `Spec::register_engine`
+	/// without patching this module.
 	fn engine<T: AsRef<Path>>(
 		cache_dir: T,
 		engine_spec: ethjson::spec::Engine,
-		params: CommonParams,
-		builtins: BTreeMap<Address, Builtin>,
-	) -> Arc<Engine> {
-		match engine_spec {
-			ethjson::spec::Engine::Null => Arc::new(NullEngine::new(params, builtins)),
-			ethjson::spec::Engine::InstantSeal => Arc::new(InstantSeal::new(params, builtins)),
-			ethjson::spec::Engine::Ethash(ethash) => Arc::new(ethereum::Ethash::new(cache_dir, params, From::from(ethash.params), builtins)),
-			ethjson::spec::Engine::BasicAuthority(basic_authority) => Arc::new(BasicAuthority::new(params, From::from(basic_authority.params), builtins)),
-			ethjson::spec::Engine::AuthorityRound(authority_round) => AuthorityRound::new(params, From::from(authority_round.params), builtins).expect("Failed to start AuthorityRound consensus engine."),
-			ethjson::spec::Engine::Tendermint(tendermint) => Tendermint::new(params, From::from(tendermint.params), builtins).expect("Failed to start the Tendermint consensus engine."),
-		}
+		machine: Arc<EthereumMachine>,
+	) -> Result<Arc<Engine>, Error> {
+		let name = engine_registry::engine_name(&engine_spec);
+		engine_registry::build_engine(name, cache_dir.as_ref(), machine, engine_spec)
 	}
 
-	// given a pre-constructor state, run all the given constructors and produce a new state and state root.
-	fn run_constructors<T: Backend>(&self, factories: &Factories, mut db: T) -> Result<T, Error> {
-		let mut root = SHA3_NULL_RLP;
-
-		// basic accounts in spec.
-		{
-			let mut t = factories.trie.create(db.as_hashdb_mut(), &mut root);
-
-			for (address, account) in self.genesis_state.get().iter() {
-				t.insert(&**address, &account.rlp())?;
-			}
-		}
-
-		for (address, account) in self.genesis_state.get().iter() {
-			db.note_non_null_account(address);
-			account.insert_additional(
-				&mut *factories.accountdb.create(db.as_hashdb_mut(), address.sha3()),
-				&factories.trie
-			);
-		}
-
-		let start_nonce = self.engine.account_start_nonce(0);
-
-		let (root, db) = {
-			let mut state = State::from_existing(
-				db,
-				root,
-				start_nonce,
-				factories.clone(),
-			)?;
-
-			// Execute contract constructors.
-			let env_info = EnvInfo {
-				number: 0,
-				author: self.author,
-				timestamp: self.timestamp,
-				difficulty: self.difficulty,
-				last_hashes: Default::default(),
-				gas_used: U256::zero(),
-				gas_limit: U256::max_value(),
-			};
+	/// Register a consensus engine factory under `name`, overriding any existing
+	/// registration (including the built-in engines) for `name`. Must be called
+	/// before loading the spec that uses it. Loading a chainspec only looks up
+	/// the six built-in engine names (`"Null"`, `"InstantSeal"`, `"Ethash"`,
+	/// `"BasicAuthority"`, `"AuthorityRound"`, `"Tendermint"`); a factory
+	/// registered under any other name is not reached by `load`.
+	pub fn register_engine(name: &str, factory: engine_registry::EngineFactory) {
+		engine_registry::register_engine(name, factory)
+	}
 
-			let from = Address::default();
-			for &(ref address, ref constructor) in self.constructors.iter() {
-				trace!(target: "spec", "run_constructors: Creating a contract at {}.", address);
-				trace!(target: "spec", "  .. root before = {}", state.root());
-				let params = ActionParams {
-					code_address: address.clone(),
-					code_hash: Some(constructor.sha3()),
-					address: address.clone(),
-					sender: from.clone(),
-					origin: from.clone(),
-					gas: U256::max_value(),
-					gas_price: Default::default(),
-					value: ActionValue::Transfer(Default::default()),
-					code: Some(Arc::new(constructor.clone())),
-					data: None,
-					call_type: CallType::None,
-				};
-
-				let mut substate = Substate::new();
-				state.kill_account(&address);
-
-				{
-					let mut exec = Executive::new(&mut state, &env_info, self.engine.as_ref());
-					if let Err(e) = exec.create(params, &mut substate, &mut NoopTracer, &mut NoopVMTracer) {
-						warn!(target: "spec", "Genesis constructor execution at {} failed: {}.", address, e);
-					}
-				}
-
-				if let Err(e) = state.commit() {
-					warn!(target: "spec", "Genesis constructor trie commit at {} failed: {}.", address, e);
-				}
-
-				trace!(target: "spec", "  .. root after = {}", state.root());
-			}
-
-			state.drop()
-		};
+	// given a pre-constructor state, run all the given constructors and produce a new state and state root.
+	fn run_constructors<T: Backend>(&self, factories: &Factories, db: T) -> Result<T, Error> {
+		let builder = StateBuilder::new(
+			self.engine.as_ref(),
+			&self.genesis_state,
+			&self.constructors,
+			self.author,
+			self.timestamp,
+			self.difficulty,
+		);
+		let (db, root, pod_state) = builder.build(factories, db)?;
 
 		*self.state_root_memo.write() = root;
+		*self.constructed_state_memo.write() = pod_state;
 		Ok(db)
 	}
 
@@ -371,8 +393,11 @@ impl Spec {
 		self.state_root_memo.read().clone()
 	}
 
+	/// Get the shared machine (chain params and builtins) that `engine` was built from.
+	pub fn machine(&self) -> &EthereumMachine { &self.machine }
+
 	/// Get common blockchain parameters.
-	pub fn params(&self) -> &CommonParams { &self.engine.params() }
+	pub fn params(&self) -> &CommonParams { self.machine().params() }
 
 	/// Get the known knodes of the network in enode format.
 	pub fn nodes(&self) -> &[String] { &self.nodes }
@@ -446,10 +471,47 @@ impl Spec {
 
 	/// Returns `false` if the memoized state root is invalid. `true` otherwise.
 	pub fn is_state_root_valid(&self) -> bool {
-		// TODO: get rid of this function and ensure state root always is valid.
-		// we're mostly there, but `self.genesis_state.root()` doesn't encompass
-		// post-constructor state.
-		*self.state_root_memo.read() == self.genesis_state.root()
+		*self.state_root_memo.read() == self.constructed_state_memo.read().root()
+	}
+
+	/// The full genesis state - including everything left behind by the genesis
+	/// constructors, not just the plain accounts listed in the chainspec -
+	/// serialized as JSON. When the chainspec gives a memoized state root the
+	/// constructors are not run, and this is the plain genesis state.
+	pub fn genesis_state_as_json(&self) -> String {
+		serde_json::to_string(&*self.constructed_state_memo.read())
+			.expect("PodState serialization cannot fail")
+	}
+
+	/// Load the full genesis state (as produced by `genesis_state_as_json`) from
+	/// JSON and re-run the constructors against it, replacing this spec's
+	/// genesis state and memoized state root.
+	pub fn set_genesis_state_from_json(&mut self, s: &str) -> Result<(), Error> {
+		let state: PodState = serde_json::from_str(s).map_err(|e| Error::from(format!("Invalid genesis state JSON: {}", e)))?;
+		self.set_genesis_state(state)
+	}
+
+	/// Cross-check this spec for internal consistency (fork transitions that
+	/// contradict each other, missing required fields, ...), returning a single
+	/// error listing every inconsistency found. Intended to catch malformed
+	/// custom chainspecs at load time rather than deep inside the EVM once the
+	/// node is already syncing.
+	pub fn validate(&self) -> Result<(), Error> {
+		let mut errors = Vec::new();
+		let p = self.params();
+
+		if let Err(e) = p.validate() {
+			errors.push(e);
+		}
+		if self.gas_limit < p.min_gas_limit {
+			errors.push(format!("genesis gas_limit ({}) is below min_gas_limit ({})", self.gas_limit, p.min_gas_limit));
+		}
+
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(Error::from(format!("Invalid chain spec: {}", errors.join("; "))))
+		}
 	}
 
 	/// Ensure that the given state DB has the trie nodes in for the genesis state.
@@ -467,12 +529,19 @@ impl Spec {
 	/// Loads spec from json file. Provide factories for executing contracts and ensuring
 	/// storage goes to the right place.
 	pub fn load<T: AsRef<Path>, R>(cache_dir: T, reader: R) -> Result<Self, String> where R: Read {
+		Self::load_with_validation(cache_dir, reader, false)
+	}
+
+	/// Loads spec from json file like `load`, additionally cross-checking the parsed
+	/// spec for internal consistency (see `validate`) before returning it. Opt-in
+	/// since it rejects chainspecs that `load` has always accepted.
+	pub fn load_with_validation<T: AsRef<Path>, R>(cache_dir: T, reader: R, validate_spec: bool) -> Result<Self, String> where R: Read {
 		fn fmt<F: ::std::fmt::Display>(f: F) -> String {
 			format!("Spec json is invalid: {}", f)
 		}
 
 		ethjson::spec::Spec::load(reader).map_err(fmt)
-			.and_then(|x| load_from(cache_dir, x).map_err(fmt))
+			.and_then(|x| load_from(cache_dir, x, validate_spec).map_err(fmt))
 	}
 
 	/// Create a new Spec which conforms to the Frontier-era Morden chain except that it's a NullEngine consensus.
@@ -560,4 +629,103 @@ mod tests {
 		let expected = H256::from_str("0000000000000000000000000000000000000000000000000000000000000001").unwrap();
 		assert_eq!(state.storage_at(&Address::from_str("0000000000000000000000000000000000000005").unwrap(), &H256::zero()).unwrap(), expected);
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn genesis_state_json_round_trip() {
+		let mut spec = Spec::new_test_constructor();
+		let json = spec.genesis_state_as_json();
+		spec.set_genesis_state_from_json(&json).unwrap();
+		assert!(spec.is_state_root_valid());
+	}
+
+	// A chainspec with no accounts and a memoized genesis `stateRoot` never runs
+	// `run_constructors` - `is_state_root_valid` (and `genesis_state_as_json`) must
+	// still see the right state, not the `constructed_state_memo` default.
+	#[test]
+	fn is_state_root_valid_with_a_memoized_root_and_no_constructors() {
+		let json = format!(r#"{{
+			"name": "TestMemoizedRoot",
+			"engine": {{ "Null": {{ "params": {{ "blockReward": "0x0" }} }} }},
+			"params": {{
+				"accountStartNonce": "0x0",
+				"maximumExtraDataSize": "0x20",
+				"minGasLimit": "0x1388",
+				"networkID": "0x1",
+				"gasLimitBoundDivisor": "0x400"
+			}},
+			"genesis": {{
+				"seal": {{ "generic": "0x0" }},
+				"difficulty": "0x20000",
+				"author": "0x0000000000000000000000000000000000000000",
+				"timestamp": "0x00",
+				"parentHash": "0x0000000000000000000000000000000000000000000000000000000000000000",
+				"extraData": "0x",
+				"gasLimit": "0x1388",
+				"stateRoot": "{:?}"
+			}},
+			"accounts": {{}}
+		}}"#, SHA3_NULL_RLP);
+
+		let spec = Spec::load(::std::env::temp_dir(), json.as_bytes()).unwrap();
+		assert!(spec.is_state_root_valid());
+		assert_eq!(spec.genesis_state_as_json(), serde_json::to_string(&PodState::default()).unwrap());
+	}
+
+	fn valid_params() -> CommonParams {
+		CommonParams {
+			gas_limit_bound_divisor: 1024.into(),
+			min_gas_limit: 1.into(),
+			network_id: 1,
+			chain_id: 1,
+			eip210_transition: BlockNumber::max_value(),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn common_params_validate_accepts_consistent_params() {
+		assert!(valid_params().validate().is_ok());
+	}
+
+	#[test]
+	fn common_params_validate_rejects_inconsistent_fork_ordering() {
+		let mut params = valid_params();
+		params.eip140_transition = 100;
+		params.eip86_transition = 50;
+		assert!(params.validate().unwrap_err().contains("eip86_transition"));
+
+		let mut params = valid_params();
+		params.eip211_transition = 100;
+		params.eip214_transition = 50;
+		assert!(params.validate().unwrap_err().contains("eip214_transition"));
+	}
+
+	#[test]
+	fn common_params_validate_rejects_missing_eip210_contract_code() {
+		let mut params = valid_params();
+		params.eip210_transition = 0;
+		params.eip210_contract_code = Vec::new();
+		assert!(params.validate().unwrap_err().contains("eip210_contract_code"));
+	}
+
+	#[test]
+	fn common_params_validate_rejects_zero_required_fields() {
+		// the derived `Default` leaves every required field unset.
+		let err = CommonParams::default().validate().unwrap_err();
+		assert!(err.contains("gas_limit_bound_divisor"));
+		assert!(err.contains("min_gas_limit"));
+		assert!(err.contains("network_id"));
+		assert!(err.contains("chain_id"));
+	}
+
+	#[test]
+	fn validate_transaction_signature_only_enforces_low_s_after_homestead() {
+		let params = CommonParams { homestead_transition: 10, ..Default::default() };
+		let high_s = U256::from_str("8000000000000000000000000000000000000000000000000000000000000000").unwrap();
+		let low_s = U256::from(1);
+
+		assert!(params.validate_transaction_signature(&params.schedule(0), &high_s));
+		assert!(!params.validate_transaction_signature(&params.schedule(10), &high_s));
+		assert!(params.validate_transaction_signature(&params.schedule(10), &low_s));
+	}
+}
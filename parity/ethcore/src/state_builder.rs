@@ -0,0 +1,152 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Builds the full genesis state of a chain.
+//!
+//! `Spec::genesis_state` only holds the plain accounts listed in the
+//! chainspec - it says nothing about the state left behind by executing the
+//! genesis constructors against them. `StateBuilder` runs those constructors
+//! (trie insertion, `insert_additional`, constructor execution via
+//! `Executive`) against a caller-supplied `Backend` and hands back the primed
+//! backend together with the complete post-constructor `PodState`, so it can
+//! be inspected, diffed or persisted independently of `Spec`.
+
+use engines::Engine;
+use error::Error;
+use executive::Executive;
+use factory::Factories;
+use pod_state::PodState;
+use state::{Backend, State, Substate};
+use trace::{NoopTracer, NoopVMTracer};
+use util::*;
+use vm::{ActionParams, ActionValue, CallType, EnvInfo};
+
+/// Runs genesis constructors against a fresh trie and produces the resulting
+/// post-constructor state.
+pub struct StateBuilder<'a> {
+	engine: &'a Engine,
+	genesis_state: &'a PodState,
+	constructors: &'a [(Address, Bytes)],
+	author: Address,
+	timestamp: u64,
+	difficulty: U256,
+}
+
+impl<'a> StateBuilder<'a> {
+	/// Create a builder for the given pre-constructor plain state and constructors.
+	pub fn new(
+		engine: &'a Engine,
+		genesis_state: &'a PodState,
+		constructors: &'a [(Address, Bytes)],
+		author: Address,
+		timestamp: u64,
+		difficulty: U256,
+	) -> Self {
+		StateBuilder {
+			engine: engine,
+			genesis_state: genesis_state,
+			constructors: constructors,
+			author: author,
+			timestamp: timestamp,
+			difficulty: difficulty,
+		}
+	}
+
+	/// Run the constructors against `db`, returning the primed backend, the
+	/// resulting state root and the complete post-constructor `PodState`.
+	pub fn build<T: Backend>(&self, factories: &Factories, mut db: T) -> Result<(T, H256, PodState), Error> {
+		let mut root = SHA3_NULL_RLP;
+
+		// basic accounts in spec.
+		{
+			let mut t = factories.trie.create(db.as_hashdb_mut(), &mut root);
+
+			for (address, account) in self.genesis_state.get().iter() {
+				t.insert(&**address, &account.rlp())?;
+			}
+		}
+
+		for (address, account) in self.genesis_state.get().iter() {
+			db.note_non_null_account(address);
+			account.insert_additional(
+				&mut *factories.accountdb.create(db.as_hashdb_mut(), address.sha3()),
+				&factories.trie
+			);
+		}
+
+		let start_nonce = self.engine.account_start_nonce(0);
+
+		let (root, pod_state, db) = {
+			let mut state = State::from_existing(
+				db,
+				root,
+				start_nonce,
+				factories.clone(),
+			)?;
+
+			let env_info = EnvInfo {
+				number: 0,
+				author: self.author,
+				timestamp: self.timestamp,
+				difficulty: self.difficulty,
+				last_hashes: Default::default(),
+				gas_used: U256::zero(),
+				gas_limit: U256::max_value(),
+			};
+
+			let from = Address::default();
+			for &(ref address, ref constructor) in self.constructors.iter() {
+				trace!(target: "spec", "StateBuilder: creating a contract at {}.", address);
+				trace!(target: "spec", "  .. root before = {}", state.root());
+				let params = ActionParams {
+					code_address: address.clone(),
+					code_hash: Some(constructor.sha3()),
+					address: address.clone(),
+					sender: from.clone(),
+					origin: from.clone(),
+					gas: U256::max_value(),
+					gas_price: Default::default(),
+					value: ActionValue::Transfer(Default::default()),
+					code: Some(Arc::new(constructor.clone())),
+					data: None,
+					call_type: CallType::None,
+				};
+
+				let mut substate = Substate::new();
+				state.kill_account(&address);
+
+				{
+					let mut exec = Executive::new(&mut state, &env_info, self.engine);
+					if let Err(e) = exec.create(params, &mut substate, &mut NoopTracer, &mut NoopVMTracer) {
+						warn!(target: "spec", "Genesis constructor execution at {} failed: {}.", address, e);
+					}
+				}
+
+				if let Err(e) = state.commit() {
+					warn!(target: "spec", "Genesis constructor trie commit at {} failed: {}.", address, e);
+				}
+
+				trace!(target: "spec", "  .. root after = {}", state.root());
+			}
+
+			let pod_state = state.to_pod();
+			let (root, db) = state.drop();
+			(root, pod_state, db)
+		};
+
+		Ok((db, root, pod_state))
+	}
+}